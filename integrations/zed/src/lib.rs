@@ -1,46 +1,270 @@
+use std::fs;
+
+use zed_extension_api::settings::LspSettings;
 use zed_extension_api::{self as zed, LanguageServerId, Result};
 
-struct SpectraExtension;
+const SERVER_REPO: &str = "adriandarian/spectra-lsp";
 
-impl zed::Extension for SpectraExtension {
-    fn new() -> Self {
-        SpectraExtension
+struct SpectraExtension {
+    cached_binary_path: Option<String>,
+    cached_binary_version: Option<String>,
+}
+
+impl SpectraExtension {
+    fn language_server_binary_path(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<String> {
+        // A spectra-lsp already on the user's PATH wins over a downloaded one; it lets people
+        // run a pip-installed or hand-built server without extra configuration.
+        if let Some(path) = worktree.which("spectra-lsp") {
+            return Ok(path);
+        }
+
+        if let Some(path) = &self.cached_binary_path {
+            if fs::metadata(path).is_ok_and(|stat| stat.is_file()) {
+                return Ok(path.clone());
+            }
+        }
+
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::CheckingForUpdate,
+        );
+        let release = zed::latest_github_release(
+            SERVER_REPO,
+            zed::GithubReleaseOptions {
+                require_assets: true,
+                pre_release: false,
+            },
+        )?;
+
+        let (os, arch) = zed::current_platform();
+        let asset_name = format!(
+            "spectra-lsp-{os}-{arch}.tar.gz",
+            os = match os {
+                zed::Os::Mac => "macos",
+                zed::Os::Linux => "linux",
+                zed::Os::Windows => "windows",
+            },
+            arch = match arch {
+                zed::Architecture::Aarch64 => "aarch64",
+                zed::Architecture::X86 => "x86",
+                zed::Architecture::X8664 => "x86_64",
+            },
+        );
+
+        let asset = release
+            .assets
+            .iter()
+            .find(|asset| asset.name == asset_name)
+            .ok_or_else(|| format!("no release asset found matching {asset_name}"))?;
+
+        let version_dir = format!("spectra-lsp-{}", release.version);
+        let binary_path = format!("{version_dir}/spectra-lsp");
+
+        // Re-download when the latest release differs from what we cached or
+        // its binary is missing on disk.
+        let needs_download = self.cached_binary_version.as_deref() != Some(&release.version)
+            || !fs::metadata(&binary_path).is_ok_and(|stat| stat.is_file());
+        if needs_download {
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::Downloading,
+            );
+
+            zed::download_file(
+                &asset.download_url,
+                &version_dir,
+                zed::DownloadedFileType::GzipTar,
+            )
+            .map_err(|err| format!("failed to download spectra-lsp: {err}"))?;
+
+            zed::make_file_executable(&binary_path)?;
+
+            // Prune older versions now that the new one is in place.
+            if let Ok(entries) = fs::read_dir(".") {
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    if name.starts_with("spectra-lsp-") && name != version_dir {
+                        fs::remove_dir_all(entry.path()).ok();
+                    }
+                }
+            }
+        }
+
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::None,
+        );
+
+        self.cached_binary_path = Some(binary_path.clone());
+        self.cached_binary_version = Some(release.version);
+        Ok(binary_path)
     }
 
-    fn language_server_command(
+    /// Runs spectra-lsp out of a Python environment when no prebuilt binary is
+    /// available: locate an interpreter and launch the server as a module.
+    ///
+    /// The extension sandbox (WIT interface) can't spawn `pip` itself, so the
+    /// package must already be installed in that environment. We surface the
+    /// exact command to run through the installation status so users aren't left
+    /// guessing — see the README "Python / virtualenv" section.
+    fn pip_install_fallback(
         &mut self,
-        _language_server_id: &LanguageServerId,
+        language_server_id: &LanguageServerId,
         worktree: &zed::Worktree,
+        args: &[String],
     ) -> Result<zed::Command> {
-        // Try to find spectra-lsp in PATH or use pip-installed version
-        let path = worktree
-            .which("spectra-lsp")
-            .unwrap_or_else(|| "spectra-lsp".to_string());
+        let Some(python) = python_interpreter(worktree) else {
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::Failed(
+                    "spectra-lsp is not installed and no Python interpreter was found. \
+                     Install it with `python -m pip install --upgrade spectra-lsp`."
+                        .to_string(),
+                ),
+            );
+            return Err("no Python interpreter found for pip fallback".to_string());
+        };
+
+        // Remind the user how to provision the package in the interpreter we
+        // picked; the status clears once the server starts successfully.
+        zed::set_language_server_installation_status(
+            language_server_id,
+            &zed::LanguageServerInstallationStatus::Failed(format!(
+                "If spectra-lsp fails to start, install it with \
+                 `{python} -m pip install --upgrade spectra-lsp`."
+            )),
+        );
 
         Ok(zed::Command {
-            command: path,
-            args: vec!["--stdio".to_string()],
+            command: python,
+            args: ["-m".to_string(), "spectra_lsp".to_string()]
+                .into_iter()
+                .chain(args.iter().cloned())
+                .collect(),
             env: Default::default(),
         })
     }
+}
+
+/// Locates a Python interpreter for the fallback, preferring a project
+/// virtualenv before a `python3` on PATH.
+fn python_interpreter(worktree: &zed::Worktree) -> Option<String> {
+    let venv = format!("{}/.venv/bin/python", worktree.root_path());
+    if fs::metadata(&venv).is_ok_and(|stat| stat.is_file()) {
+        return Some(venv);
+    }
+    worktree.which("python3")
+}
+
+impl zed::Extension for SpectraExtension {
+    fn new() -> Self {
+        SpectraExtension {
+            cached_binary_path: None,
+            cached_binary_version: None,
+        }
+    }
+
+    fn language_server_command(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<zed::Command> {
+        let binary_settings = LspSettings::for_worktree("spectra", worktree)
+            .ok()
+            .and_then(|settings| settings.binary);
+
+        // User-provided arguments override our defaults in every branch; env
+        // overrides aren't supported by the extension API (see README).
+        let args = binary_settings
+            .as_ref()
+            .and_then(|binary| binary.arguments.clone())
+            .unwrap_or_else(|| vec!["--stdio".to_string()]);
+
+        // A user-specified binary wins over everything: use its path verbatim.
+        if let Some(path) = binary_settings.as_ref().and_then(|binary| binary.path.clone()) {
+            return Ok(zed::Command {
+                command: path,
+                args,
+                env: Default::default(),
+            });
+        }
+
+        // Prefer a managed GitHub binary; if no release asset applies to this
+        // platform, fall back to a pip/virtualenv installation of the server.
+        match self.language_server_binary_path(language_server_id, worktree) {
+            Ok(path) => Ok(zed::Command {
+                command: path,
+                args,
+                env: Default::default(),
+            }),
+            Err(github_err) => self
+                .pip_install_fallback(language_server_id, worktree, &args)
+                .map_err(|pip_err| {
+                    format!("no spectra-lsp binary available ({github_err}); {pip_err}")
+                }),
+        }
+    }
 
     fn language_server_initialization_options(
         &mut self,
         _language_server_id: &LanguageServerId,
         _worktree: &zed::Worktree,
     ) -> Result<Option<zed::serde_json::Value>> {
-        Ok(Some(zed::serde_json::json!({
-            "spectra": {
-                "validation": {
-                    "validateOnSave": true,
-                    "validateOnType": true
-                },
-                "diagnostics": {
-                    "showWarnings": true,
-                    "showHints": true
-                }
+        Ok(Some(default_config()))
+    }
+
+    fn language_server_workspace_configuration(
+        &mut self,
+        _language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<zed::serde_json::Value>> {
+        let mut configuration = default_config();
+
+        // Merge any `settings` the user provides in .zed/settings.json over our
+        // defaults so projects can tune validation/diagnostics without a rebuild.
+        if let Some(user_settings) = LspSettings::for_worktree("spectra", worktree)
+            .ok()
+            .and_then(|settings| settings.settings)
+        {
+            merge_json(&mut configuration, &user_settings);
+        }
+
+        Ok(Some(configuration))
+    }
+}
+
+/// The built-in validation/diagnostics configuration, shared by both the
+/// initialization options and the workspace-configuration defaults.
+fn default_config() -> zed::serde_json::Value {
+    zed::serde_json::json!({
+        "spectra": {
+            "validation": {
+                "validateOnSave": true,
+                "validateOnType": true
+            },
+            "diagnostics": {
+                "showWarnings": true,
+                "showHints": true
+            }
+        }
+    })
+}
+
+/// Recursively merges `overlay` into `base`, preferring overlay values on
+/// conflicts while preserving sibling keys on both sides.
+fn merge_json(base: &mut zed::serde_json::Value, overlay: &zed::serde_json::Value) {
+    match (base, overlay) {
+        (zed::serde_json::Value::Object(base), zed::serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge_json(base.entry(key.clone()).or_insert(zed::serde_json::Value::Null), value);
             }
-        })))
+        }
+        (base, overlay) => *base = overlay.clone(),
     }
 }
 